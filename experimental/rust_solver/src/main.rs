@@ -36,6 +36,21 @@ pub struct KeplerConfig {
     pub water_block_start_penalty_sek: f64,
     pub defer_up_to_hours: f64,
     pub enable_export: bool,
+    #[serde(default)]
+    pub prevent_simultaneous_charge_discharge: bool,
+    pub max_duration_hours: Option<f64>,
+    #[serde(default)]
+    pub ashp_max_heat_kw: f64,
+    #[serde(default)]
+    pub space_heat_comfort_penalty_sek: f64,
+    #[serde(default)]
+    pub boiler_max_heat_kw: f64,
+    #[serde(default)]
+    pub boiler_efficiency: f64,
+    #[serde(default)]
+    pub fuel_price_sek_per_kwh: f64,
+    #[serde(default)]
+    pub reserve_requirement_kw: f64,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -46,18 +61,44 @@ pub struct KeplerInputSlot {
     pub pv_kwh: f64,
     pub import_price_sek_kwh: f64,
     pub export_price_sek_kwh: f64,
+    #[serde(default)]
+    pub cop: f64,
+    #[serde(default)]
+    pub space_heat_kwh: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EvSession {
+    pub arrival_slot: usize,
+    pub departure_slot: usize,
+    pub energy_required_kwh: f64,
+    pub max_charge_power_kw: f64,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct KeplerInput {
     pub slots: Vec<KeplerInputSlot>,
     pub initial_soc_kwh: f64,
+    #[serde(default)]
+    pub ev_sessions: Vec<EvSession>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct KeplerScenario {
+    pub probability: f64,
+    pub input: KeplerInput,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SidecarInput {
     pub input: KeplerInput,
     pub config: KeplerConfig,
+    /// Weighted price/PV/load scenarios for two-stage stochastic dispatch.
+    /// When present and non-empty, the first slot's charge/discharge/water
+    /// decisions are forced identical across all scenarios (non-anticipativity)
+    /// and the deterministic `input` field above is ignored.
+    #[serde(default)]
+    pub scenarios: Option<Vec<KeplerScenario>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -74,6 +115,10 @@ pub struct KeplerResultSlot {
     pub export_price_sek_kwh: f64,
     pub water_heat_kw: f64,
     pub terminal_credit_sek: f64,
+    pub ashp_heat_kwh: f64,
+    pub boiler_heat_kwh: f64,
+    pub ev_charge_kwh: f64,
+    pub reserve_kwh: f64,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -83,6 +128,10 @@ pub struct KeplerResult {
     pub is_optimal: bool,
     pub status_msg: String,
     pub solve_time_ms: f64,
+    /// Present only for scenario-based solves: expected cost of each scenario
+    /// (in scenario order), evaluated under the shared committed first-slot
+    /// action plus that scenario's own recourse.
+    pub scenario_costs_sek: Option<Vec<f64>>,
 }
 
 fn main() -> Result<()> {
@@ -125,6 +174,12 @@ fn main() -> Result<()> {
 }
 
 fn solve_kepler(sidecar_input: &SidecarInput) -> Result<KeplerResult> {
+    if let Some(scenarios) = &sidecar_input.scenarios {
+        if !scenarios.is_empty() {
+            return solve_kepler_stochastic(sidecar_input, scenarios);
+        }
+    }
+
     let config = &sidecar_input.config;
     let input = &sidecar_input.input;
     let t_total = input.slots.len();
@@ -136,6 +191,7 @@ fn solve_kepler(sidecar_input: &SidecarInput) -> Result<KeplerResult> {
             is_optimal: true,
             status_msg: "No slots".to_string(),
             solve_time_ms: 0.0,
+            scenario_costs_sek: None,
         });
     }
 
@@ -147,11 +203,21 @@ fn solve_kepler(sidecar_input: &SidecarInput) -> Result<KeplerResult> {
     let curtailment_penalty = 0.1;
     let load_shedding_penalty = 10000.0;
     let import_breach_penalty = 5000.0; // from config defaults effectively
+    let ev_unmet_energy_penalty = 10000.0;
+    let reserve_violation_penalty = 1000.0;
 
     let mut variables = good_lp::variables!();
 
-    let min_soc_kwh = config.min_soc_percent * config.capacity_kwh / 100.0;
-    let max_soc_kwh = config.max_soc_percent * config.capacity_kwh / 100.0;
+    // A storage bank can be specified either as capacity_kwh directly, or as
+    // max_charge_power_kw + max_duration_hours (power + duration), in which
+    // case the derived energy window takes precedence.
+    let capacity_kwh = match config.max_duration_hours {
+        Some(hours) if hours > 0.0 => config.max_charge_power_kw * hours,
+        _ => config.capacity_kwh,
+    };
+
+    let min_soc_kwh = config.min_soc_percent * capacity_kwh / 100.0;
+    let max_soc_kwh = config.max_soc_percent * capacity_kwh / 100.0;
     let max_charge_kwh = config.max_charge_power_kw * slot_duration_h;
     let max_discharge_kwh = config.max_discharge_power_kw * slot_duration_h;
 
@@ -160,6 +226,7 @@ fn solve_kepler(sidecar_input: &SidecarInput) -> Result<KeplerResult> {
     let mut grid_export = Vec::with_capacity(t_total);
     let mut charge = Vec::with_capacity(t_total);
     let mut discharge = Vec::with_capacity(t_total);
+    let mut is_charging: Vec<good_lp::Variable> = Vec::new();
     let mut water_heat = Vec::with_capacity(t_total);
     let mut water_start = Vec::with_capacity(t_total);
     let mut soc = Vec::with_capacity(t_total + 1);
@@ -181,6 +248,45 @@ fn solve_kepler(sidecar_input: &SidecarInput) -> Result<KeplerResult> {
     let mut gap_violation_2: Vec<good_lp::Variable> = Vec::new();
 
     let water_enabled = config.water_heating_power_kw > 0.0;
+    let ashp_enabled = config.ashp_max_heat_kw > 0.0;
+    let boiler_enabled = config.boiler_max_heat_kw > 0.0;
+    let space_heat_enabled = ashp_enabled || boiler_enabled;
+
+    // A slot with no COP can't convert ASHP electricity into heat, so it would
+    // silently deliver zero heat output (forcing the comfort-penalty slack)
+    // instead of signalling that the pump is unavailable. Treat `cop <= 0.0`
+    // as a config error rather than a quiet zero whenever that slot actually
+    // needs space heat.
+    if ashp_enabled {
+        for (t, slot) in input.slots.iter().enumerate() {
+            if slot.space_heat_kwh > 0.0 && slot.cop <= 0.0 {
+                return Err(anyhow::anyhow!(
+                    "Slot {} requires {} kWh of space heat but has cop <= 0.0 while ASHP is enabled; \
+                     set a positive cop or disable ASHP for that slot",
+                    t,
+                    slot.space_heat_kwh
+                ));
+            }
+        }
+    }
+
+    // A zero (the default) or negative boiler_efficiency would make the boiler
+    // burn fuel while delivering no (or negative) heat in the model, silently
+    // pushing the demand into the shortfall slack, so treat it like the ASHP
+    // cop check: a config error rather than a silent zero.
+    if boiler_enabled && config.boiler_efficiency <= 0.0 {
+        return Err(anyhow::anyhow!(
+            "boiler_max_heat_kw > 0.0 but boiler_efficiency <= 0.0; set a positive boiler_efficiency or disable the boiler"
+        ));
+    }
+
+    let mut ashp_elec = Vec::with_capacity(t_total);
+    let mut boiler_fuel = Vec::with_capacity(t_total);
+    let mut space_heat_shortfall = Vec::with_capacity(t_total);
+
+    let reserve_enabled = config.reserve_requirement_kw > 0.0;
+    let mut reserve = Vec::with_capacity(t_total);
+    let mut reserve_violation = Vec::with_capacity(t_total);
 
     for _ in 0..t_total {
         grid_import.push(variables.add(variable().min(0.0)));
@@ -188,6 +294,10 @@ fn solve_kepler(sidecar_input: &SidecarInput) -> Result<KeplerResult> {
         charge.push(variables.add(variable().min(0.0).max(max_charge_kwh)));
         discharge.push(variables.add(variable().min(0.0).max(max_discharge_kwh)));
 
+        if config.prevent_simultaneous_charge_discharge {
+            is_charging.push(variables.add(variable().binary()));
+        }
+
         // Safety Nets
         curtailment.push(variables.add(variable().min(0.0)));
         load_shedding.push(variables.add(variable().min(0.0)));
@@ -208,12 +318,27 @@ fn solve_kepler(sidecar_input: &SidecarInput) -> Result<KeplerResult> {
                  gap_violation_2.push(variables.add(variable().min(0.0)));
             }
         }
+
+        if ashp_enabled {
+            ashp_elec.push(variables.add(variable().min(0.0)));
+        }
+        if boiler_enabled {
+            boiler_fuel.push(variables.add(variable().min(0.0)));
+        }
+        if space_heat_enabled {
+            space_heat_shortfall.push(variables.add(variable().min(0.0)));
+        }
+
+        if reserve_enabled {
+            reserve.push(variables.add(variable().min(0.0)));
+            reserve_violation.push(variables.add(variable().min(0.0)));
+        }
     }
 
     for _ in 0..=t_total {
         // SoC variable soft bounds
         // Fix: Use 0.0 and capacity as Hard bounds. Use constraints for Soft min/max.
-        soc.push(variables.add(variable().min(0.0).max(config.capacity_kwh)));
+        soc.push(variables.add(variable().min(0.0).max(capacity_kwh)));
         soc_violation.push(variables.add(variable().min(0.0)));
     }
 
@@ -221,6 +346,23 @@ fn solve_kepler(sidecar_input: &SidecarInput) -> Result<KeplerResult> {
     let target_under_violation = variables.add(variable().min(0.0));
     let target_over_violation = variables.add(variable().min(0.0));
 
+    // EV Charging Sessions
+    // One continuous variable per session per slot inside its plug-in window
+    // (bounded to that window only), plus an unmet-energy slack per session.
+    let ev_sessions = &input.ev_sessions;
+    let mut ev_charge: Vec<Vec<Option<good_lp::Variable>>> = Vec::with_capacity(ev_sessions.len());
+    let mut ev_unmet_energy = Vec::with_capacity(ev_sessions.len());
+
+    for session in ev_sessions {
+        let max_charge_kwh_per_slot = session.max_charge_power_kw * slot_duration_h;
+        let mut session_vars = vec![None; t_total];
+        for t in session.arrival_slot..session.departure_slot.min(t_total) {
+            session_vars[t] = Some(variables.add(variable().min(0.0).max(max_charge_kwh_per_slot)));
+        }
+        ev_charge.push(session_vars);
+        ev_unmet_energy.push(variables.add(variable().min(0.0)));
+    }
+
     // Objective function
     let mut objective = Expression::from(0.0);
 
@@ -245,6 +387,23 @@ fn solve_kepler(sidecar_input: &SidecarInput) -> Result<KeplerResult> {
         if water_enabled && config.water_block_start_penalty_sek > 0.0 {
             objective += water_start[t] * config.water_block_start_penalty_sek;
         }
+
+        if space_heat_enabled {
+            objective += space_heat_shortfall[t] * config.space_heat_comfort_penalty_sek;
+        }
+        if boiler_enabled {
+            objective += boiler_fuel[t] * config.fuel_price_sek_per_kwh;
+        }
+    }
+
+    // EV Unmet Energy Penalty
+    for v in &ev_unmet_energy {
+        objective += *v * ev_unmet_energy_penalty;
+    }
+
+    // Reserve Shortfall Penalty
+    for v in &reserve_violation {
+        objective += *v * reserve_violation_penalty;
     }
 
     // SoC Violation Penalty
@@ -285,13 +444,57 @@ fn solve_kepler(sidecar_input: &SidecarInput) -> Result<KeplerResult> {
         } else {
             Expression::from(0.0)
         };
+        let ashp_load_kwh = if ashp_enabled {
+            ashp_elec[t].into_expression()
+        } else {
+            Expression::from(0.0)
+        };
+        let ev_load_kwh: Expression = ev_charge
+            .iter()
+            .filter_map(|session_vars| session_vars[t])
+            .map(|v| v.into_expression())
+            .sum();
 
         // 1. Energy Balance (with Safety Nets)
-        // load + water + charge + export + curtailment == pv + discharge + import + shading
-        let demand = Expression::from(slot.load_kwh) + charge[t] + grid_export[t] + water_load_kwh + curtailment[t];
+        // load + water + ashp + ev + charge + export + curtailment == pv + discharge + import + shading
+        let demand = Expression::from(slot.load_kwh) + charge[t] + grid_export[t] + water_load_kwh + ashp_load_kwh + ev_load_kwh + curtailment[t];
         let supply = Expression::from(slot.pv_kwh) + discharge[t] + grid_import[t] + load_shedding[t];
         model.add_constraint(good_lp::Constraint::from(demand.eq(supply)));
 
+        // 1b. Space Heating Demand (Merit Order: Heat Pump then Boiler)
+        // Each source's delivered heat is capped by its own capacity; together
+        // with the shortfall slack they must cover space_heat_kwh[t]. Since
+        // the heat pump has no fuel cost, the LP favors it whenever its
+        // capacity and COP make it cheaper than the boiler. The resistive
+        // `water_heat` element is intentionally not wired in here: it models
+        // domestic hot water only (see the water-heating constraints below)
+        // and never competes for space_heat_kwh. With no ASHP and no boiler
+        // none of this is built, so space_heat_kwh is ignored (no constraint,
+        // no shortfall penalty).
+        if space_heat_enabled {
+            let mut delivered_heat = Expression::from(0.0);
+
+            if ashp_enabled {
+                let ashp_heat = ashp_elec[t].into_expression() * slot.cop;
+                model.add_constraint(good_lp::Constraint::from(
+                    ashp_heat.clone().leq(config.ashp_max_heat_kw * slot_duration_h),
+                ));
+                delivered_heat += ashp_heat;
+            }
+
+            if boiler_enabled {
+                let boiler_heat = boiler_fuel[t].into_expression() * config.boiler_efficiency;
+                model.add_constraint(good_lp::Constraint::from(
+                    boiler_heat.clone().leq(config.boiler_max_heat_kw * slot_duration_h),
+                ));
+                delivered_heat += boiler_heat;
+            }
+
+            model.add_constraint(good_lp::Constraint::from(
+                (delivered_heat + space_heat_shortfall[t]).geq(slot.space_heat_kwh),
+            ));
+        }
+
         // 2. Battery Dynamics
         let eff_d = if config.discharge_efficiency > 0.0 { config.discharge_efficiency } else { 1.0 };
         let next_soc_expr = soc[t].into_expression() + charge[t] * config.charge_efficiency - discharge[t] / eff_d;
@@ -304,6 +507,38 @@ fn solve_kepler(sidecar_input: &SidecarInput) -> Result<KeplerResult> {
         // Python: prob += soc[t] <= max_soc_kwh
         model.add_constraint(good_lp::Constraint::from(soc[t].into_expression().leq(max_soc_kwh)));
 
+        // 2b. Charge/Discharge Exclusivity (MIP)
+        // Without this, the LP can exploit round-trip efficiency losses to
+        // charge and discharge in the same slot purely to burn energy.
+        if config.prevent_simultaneous_charge_discharge {
+            model.add_constraint(good_lp::Constraint::from(
+                charge[t].into_expression().leq(is_charging[t] * max_charge_kwh),
+            ));
+            model.add_constraint(good_lp::Constraint::from(
+                discharge[t]
+                    .into_expression()
+                    .leq(max_discharge_kwh - is_charging[t] * max_discharge_kwh),
+            ));
+        }
+
+        // 2c. Operating Reserve Headroom (Soft)
+        // reserve[t] is the dispatchable discharge headroom left in the pack,
+        // bounded by both remaining discharge capacity and remaining usable
+        // energy above min_soc_kwh. A shortfall is penalized rather than
+        // infeasible, so tight slots don't blow up the solve.
+        if reserve_enabled {
+            model.add_constraint(good_lp::Constraint::from(
+                reserve[t].into_expression().leq(max_discharge_kwh - discharge[t]),
+            ));
+            model.add_constraint(good_lp::Constraint::from(
+                reserve[t].into_expression().leq(soc[t].into_expression() - min_soc_kwh),
+            ));
+            model.add_constraint(good_lp::Constraint::from(
+                (reserve[t].into_expression() + reserve_violation[t])
+                    .geq(config.reserve_requirement_kw * slot_duration_h),
+            ));
+        }
+
         // 3. Grid Import Limit (Soft)
         if let Some(limit_kw) = config.grid_import_limit_kw {
             let limit_kwh = limit_kw * slot_duration_h;
@@ -367,6 +602,14 @@ fn solve_kepler(sidecar_input: &SidecarInput) -> Result<KeplerResult> {
     }
 
 
+    // 7b. EV Required Energy By Departure
+    for (i, session) in ev_sessions.iter().enumerate() {
+        let window_sum: Expression = ev_charge[i].iter().filter_map(|v| *v).map(|v| v.into_expression()).sum();
+        model.add_constraint(good_lp::Constraint::from(
+            (window_sum + ev_unmet_energy[i]).geq(session.energy_required_kwh),
+        ));
+    }
+
     // 8. Water Heating Daily Requirement
     if water_enabled && config.water_heating_min_kwh > 0.0 {
         let mut slots_by_day: BTreeMap<chrono::NaiveDate, Vec<usize>> = BTreeMap::new();
@@ -444,6 +687,22 @@ fn solve_kepler(sidecar_input: &SidecarInput) -> Result<KeplerResult> {
         } else {
             0.0
         };
+        let ashp_heat_kwh = if ashp_enabled {
+            solution.value(ashp_elec[t]) * slot.cop
+        } else {
+            0.0
+        };
+        let boiler_heat_kwh = if boiler_enabled {
+            solution.value(boiler_fuel[t]) * config.boiler_efficiency
+        } else {
+            0.0
+        };
+        let ev_charge_kwh: f64 = ev_charge
+            .iter()
+            .filter_map(|session_vars| session_vars[t])
+            .map(|v| solution.value(v))
+            .sum();
+        let reserve_kwh = if reserve_enabled { solution.value(reserve[t]) } else { 0.0 };
 
         result_slots.push(KeplerResultSlot {
             start_time: slot.start_time,
@@ -459,6 +718,10 @@ fn solve_kepler(sidecar_input: &SidecarInput) -> Result<KeplerResult> {
             export_price_sek_kwh: slot.export_price_sek_kwh,
             water_heat_kw: w_kw,
             terminal_credit_sek: 0.0,
+            ashp_heat_kwh,
+            boiler_heat_kwh,
+            ev_charge_kwh,
+            reserve_kwh,
         });
     }
 
@@ -468,5 +731,526 @@ fn solve_kepler(sidecar_input: &SidecarInput) -> Result<KeplerResult> {
         is_optimal: true,
         status_msg: "Optimal".to_string(),
         solve_time_ms: solve_duration_ms,
+        scenario_costs_sek: None,
+    })
+}
+
+/// Two-stage stochastic dispatch: builds a single extensive-form MILP that
+/// replicates the core battery/water model once per scenario, ties the
+/// first slot's charge/discharge/water decisions together across scenarios
+/// (non-anticipativity), and minimizes the probability-weighted cost. Unlike
+/// `solve_kepler`, the richer subsystems (ASHP, boiler, EV, reserve) are not
+/// modeled here; each scenario only carries energy-balance, battery-dynamics,
+/// and a reduced water-heating model (one `water_heating_min_kwh` minimum
+/// over the whole horizon, not split per day and with no credit for
+/// `water_heated_today_kwh`; gap/spacing, block-start, and force-on are not
+/// enforced). Grid
+/// import/export power caps are enforced per scenario so the committed
+/// first-slot action cannot exceed the physical connection limit.
+///
+/// Because those subsystems aren't modeled, this returns an `Err` if ASHP,
+/// boiler, reserve, or any scenario's EV sessions are configured, rather than
+/// silently dispatching as if they didn't exist. It also requires every
+/// scenario's first slot (load/PV/prices) and `initial_soc_kwh` to agree,
+/// since non-anticipativity only ties the *decisions* together and a
+/// committed first-slot action is meaningless if scenarios disagree on the
+/// here-and-now data it was optimized against.
+fn solve_kepler_stochastic(
+    sidecar_input: &SidecarInput,
+    scenarios: &[KeplerScenario],
+) -> Result<KeplerResult> {
+    let config = &sidecar_input.config;
+    let n_scenarios = scenarios.len();
+    let t_total = scenarios[0].input.slots.len();
+
+    // Scenario mode only models energy-balance, battery-dynamics, and a
+    // reduced water-heating constraint (see doc comment above). Silently
+    // ignoring a configured EV session, ASHP, boiler, or reserve requirement
+    // would mean committing a first-slot action that doesn't actually serve
+    // a requirement the caller asked for, so refuse instead.
+    if config.ashp_max_heat_kw > 0.0 {
+        return Err(anyhow::anyhow!(
+            "Scenario-based dispatch does not model the ASHP subsystem (ashp_max_heat_kw > 0); disable scenarios or ashp_max_heat_kw"
+        ));
+    }
+    if config.boiler_max_heat_kw > 0.0 {
+        return Err(anyhow::anyhow!(
+            "Scenario-based dispatch does not model the boiler subsystem (boiler_max_heat_kw > 0); disable scenarios or boiler_max_heat_kw"
+        ));
+    }
+    if config.reserve_requirement_kw > 0.0 {
+        return Err(anyhow::anyhow!(
+            "Scenario-based dispatch does not model the reserve-headroom subsystem (reserve_requirement_kw > 0); disable scenarios or reserve_requirement_kw"
+        ));
+    }
+    for (s, scenario) in scenarios.iter().enumerate() {
+        if !scenario.input.ev_sessions.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Scenario {} carries {} EV session(s), but scenario-based dispatch does not model EV charging; disable scenarios or ev_sessions",
+                s,
+                scenario.input.ev_sessions.len()
+            ));
+        }
+        if scenario.input.slots.len() != t_total {
+            return Err(anyhow::anyhow!(
+                "Scenario {} has {} slots, expected {} (all scenarios must share the same horizon)",
+                s,
+                scenario.input.slots.len(),
+                t_total
+            ));
+        }
+    }
+
+    // Non-anticipativity ties scenario[0..n]'s first-slot *decisions*
+    // together, but that's only meaningful if the first slot's exogenous
+    // data (what the decisions are being optimized against) also agrees.
+    if t_total > 0 {
+        let s0 = &scenarios[0].input.slots[0];
+        for (s, scenario) in scenarios.iter().enumerate().skip(1) {
+            let slot = &scenario.input.slots[0];
+            let agrees = (slot.load_kwh - s0.load_kwh).abs() < 1e-9
+                && (slot.pv_kwh - s0.pv_kwh).abs() < 1e-9
+                && (slot.import_price_sek_kwh - s0.import_price_sek_kwh).abs() < 1e-9
+                && (slot.export_price_sek_kwh - s0.export_price_sek_kwh).abs() < 1e-9
+                && (scenario.input.initial_soc_kwh - scenarios[0].input.initial_soc_kwh).abs() < 1e-9;
+            if !agrees {
+                return Err(anyhow::anyhow!(
+                    "Scenario {} disagrees with scenario 0 on here-and-now (slot 0) data or initial_soc_kwh; \
+                     all scenarios must share identical first-slot load/pv/prices and initial SoC",
+                    s
+                ));
+            }
+        }
+    }
+
+    if t_total == 0 {
+        return Ok(KeplerResult {
+            slots: Vec::new(),
+            total_cost_sek: 0.0,
+            is_optimal: true,
+            status_msg: "No slots".to_string(),
+            solve_time_ms: 0.0,
+            scenario_costs_sek: Some(vec![0.0; n_scenarios]),
+        });
+    }
+
+    let s0 = &scenarios[0].input.slots[0];
+    let slot_duration_h = (s0.end_time - s0.start_time).num_seconds() as f64 / 3600.0;
+
+    // Hardcoded Penalties (Matching Python kepler.py constants)
+    let min_soc_penalty = 1000.0;
+    let curtailment_penalty = 0.1;
+    let load_shedding_penalty = 10000.0;
+
+    let capacity_kwh = match config.max_duration_hours {
+        Some(hours) if hours > 0.0 => config.max_charge_power_kw * hours,
+        _ => config.capacity_kwh,
+    };
+    let min_soc_kwh = config.min_soc_percent * capacity_kwh / 100.0;
+    let max_soc_kwh = config.max_soc_percent * capacity_kwh / 100.0;
+    let max_charge_kwh = config.max_charge_power_kw * slot_duration_h;
+    let max_discharge_kwh = config.max_discharge_power_kw * slot_duration_h;
+    let water_enabled = config.water_heating_power_kw > 0.0;
+
+    let mut variables = good_lp::variables!();
+
+    // Per-scenario variable vectors, indexed [scenario][t].
+    let mut grid_import = vec![Vec::with_capacity(t_total); n_scenarios];
+    let mut grid_export = vec![Vec::with_capacity(t_total); n_scenarios];
+    let mut charge = vec![Vec::with_capacity(t_total); n_scenarios];
+    let mut discharge = vec![Vec::with_capacity(t_total); n_scenarios];
+    let mut curtailment = vec![Vec::with_capacity(t_total); n_scenarios];
+    let mut load_shedding = vec![Vec::with_capacity(t_total); n_scenarios];
+    let mut water_heat = vec![Vec::with_capacity(t_total); n_scenarios];
+    let mut soc = vec![Vec::with_capacity(t_total + 1); n_scenarios];
+    let mut soc_violation = vec![Vec::with_capacity(t_total + 1); n_scenarios];
+
+    for s in 0..n_scenarios {
+        for _ in 0..t_total {
+            grid_import[s].push(variables.add(variable().min(0.0)));
+            grid_export[s].push(variables.add(variable().min(0.0)));
+            charge[s].push(variables.add(variable().min(0.0).max(max_charge_kwh)));
+            discharge[s].push(variables.add(variable().min(0.0).max(max_discharge_kwh)));
+            curtailment[s].push(variables.add(variable().min(0.0)));
+            load_shedding[s].push(variables.add(variable().min(0.0)));
+
+            if water_enabled {
+                water_heat[s].push(variables.add(variable().binary()));
+            }
+        }
+        for _ in 0..=t_total {
+            soc[s].push(variables.add(variable().min(0.0).max(capacity_kwh)));
+            soc_violation[s].push(variables.add(variable().min(0.0)));
+        }
+    }
+
+    // Objective: probability-weighted sum of per-scenario objectives.
+    let mut objective = Expression::from(0.0);
+    let mut scenario_objectives = Vec::with_capacity(n_scenarios);
+
+    for s in 0..n_scenarios {
+        let mut scenario_objective = Expression::from(0.0);
+        for t in 0..t_total {
+            let slot = &scenarios[s].input.slots[t];
+            scenario_objective += grid_import[s][t] * slot.import_price_sek_kwh;
+            scenario_objective -= grid_export[s][t] * (slot.export_price_sek_kwh - config.export_threshold_sek_per_kwh);
+            scenario_objective += (charge[s][t] + discharge[s][t]) * config.wear_cost_sek_per_kwh;
+            scenario_objective += curtailment[s][t] * curtailment_penalty;
+            scenario_objective += load_shedding[s][t] * load_shedding_penalty;
+        }
+        for t in 0..=t_total {
+            scenario_objective += soc_violation[s][t] * min_soc_penalty;
+        }
+        scenario_objective -= soc[s][t_total] * config.terminal_value_sek_kwh;
+
+        objective += scenario_objective.clone() * scenarios[s].probability;
+        scenario_objectives.push(scenario_objective);
+    }
+
+    let mut model = variables.minimise(objective.clone()).using(highs);
+
+    for s in 0..n_scenarios {
+        model.add_constraint(good_lp::Constraint::from(
+            soc[s][0].into_expression().eq(scenarios[s].input.initial_soc_kwh),
+        ));
+
+        for t in 0..t_total {
+            let slot = &scenarios[s].input.slots[t];
+            let water_load_kwh = if water_enabled {
+                water_heat[s][t].into_expression() * config.water_heating_power_kw * slot_duration_h
+            } else {
+                Expression::from(0.0)
+            };
+
+            // 1. Energy Balance (with Safety Nets)
+            let demand = Expression::from(slot.load_kwh) + charge[s][t] + grid_export[s][t] + water_load_kwh + curtailment[s][t];
+            let supply = Expression::from(slot.pv_kwh) + discharge[s][t] + grid_import[s][t] + load_shedding[s][t];
+            model.add_constraint(good_lp::Constraint::from(demand.eq(supply)));
+
+            // 2. Battery Dynamics
+            let eff_d = if config.discharge_efficiency > 0.0 { config.discharge_efficiency } else { 1.0 };
+            let next_soc_expr = soc[s][t].into_expression() + charge[s][t] * config.charge_efficiency - discharge[s][t] / eff_d;
+            model.add_constraint(good_lp::Constraint::from(soc[s][t + 1].into_expression().eq(next_soc_expr)));
+
+            // Soft Min SoC
+            model.add_constraint(good_lp::Constraint::from(soc[s][t].into_expression().geq(min_soc_kwh - soc_violation[s][t])));
+
+            // HARD Max SoC
+            model.add_constraint(good_lp::Constraint::from(soc[s][t].into_expression().leq(max_soc_kwh)));
+
+            if !config.enable_export {
+                model.add_constraint(good_lp::Constraint::from(grid_export[s][t].into_expression().eq(0.0)));
+            }
+
+            // Grid Connection Limits
+            // Unlike the deterministic path, grid_import_limit_kw is treated
+            // as a hard cap here (no import_breach slack) to keep the
+            // extensive-form model's scope to the core subsystems.
+            if let Some(limit_kw) = config.grid_import_limit_kw {
+                model.add_constraint(good_lp::Constraint::from(
+                    grid_import[s][t].into_expression().leq(limit_kw * slot_duration_h),
+                ));
+            }
+            if let Some(limit_kw) = config.max_import_power_kw {
+                model.add_constraint(good_lp::Constraint::from(
+                    grid_import[s][t].into_expression().leq(limit_kw * slot_duration_h),
+                ));
+            }
+            if let Some(limit_kw) = config.max_export_power_kw {
+                model.add_constraint(good_lp::Constraint::from(
+                    grid_export[s][t].into_expression().leq(limit_kw * slot_duration_h),
+                ));
+            }
+        }
+
+        // Terminal Soft Min SoC
+        model.add_constraint(good_lp::Constraint::from(
+            soc[s][t_total].into_expression().geq(min_soc_kwh - soc_violation[s][t_total]),
+        ));
+
+        // Water Heating Requirement (whole horizon, not split per day)
+        if water_enabled && config.water_heating_min_kwh > 0.0 {
+            let day_req = config.water_heating_min_kwh;
+            let day_sum: Expression = water_heat[s].iter().sum();
+            model.add_constraint(good_lp::Constraint::from(
+                (day_sum * config.water_heating_power_kw * slot_duration_h).geq(day_req),
+            ));
+        }
+    }
+
+    // Non-Anticipativity: the here-and-now (first-slot) decisions must be
+    // identical across all scenarios, since the dispatch controller commits
+    // to them before the uncertainty resolves.
+    for s in 1..n_scenarios {
+        model.add_constraint(good_lp::Constraint::from(charge[0][0].into_expression().eq(charge[s][0])));
+        model.add_constraint(good_lp::Constraint::from(discharge[0][0].into_expression().eq(discharge[s][0])));
+        if water_enabled {
+            model.add_constraint(good_lp::Constraint::from(water_heat[0][0].into_expression().eq(water_heat[s][0])));
+        }
+    }
+
+    let solve_start = Instant::now();
+    let solution = model.solve()
+        .map_err(|e| anyhow::anyhow!("Solver failed: {:?}. Is optimal: {}", e, false))?;
+    let solve_duration_ms = solve_start.elapsed().as_secs_f64() * 1000.0;
+
+    // Report the committed first-slot action (identical across scenarios)
+    // followed by scenario 0's recourse path as the reference trajectory.
+    let mut result_slots = Vec::with_capacity(t_total);
+    for t in 0..t_total {
+        let slot = &scenarios[0].input.slots[t];
+        let w_kw = if water_enabled {
+            if solution.value(water_heat[0][t]) > 0.5 { config.water_heating_power_kw } else { 0.0 }
+        } else {
+            0.0
+        };
+
+        result_slots.push(KeplerResultSlot {
+            start_time: slot.start_time,
+            end_time: slot.end_time,
+            charge_kwh: solution.value(charge[0][t]),
+            discharge_kwh: solution.value(discharge[0][t]),
+            grid_import_kwh: solution.value(grid_import[0][t]),
+            grid_export_kwh: solution.value(grid_export[0][t]),
+            soc_kwh: solution.value(soc[0][t + 1]),
+            cost_sek: (solution.value(grid_import[0][t]) * slot.import_price_sek_kwh)
+                    - (solution.value(grid_export[0][t]) * slot.export_price_sek_kwh),
+            import_price_sek_kwh: slot.import_price_sek_kwh,
+            export_price_sek_kwh: slot.export_price_sek_kwh,
+            water_heat_kw: w_kw,
+            terminal_credit_sek: 0.0,
+            ashp_heat_kwh: 0.0,
+            boiler_heat_kwh: 0.0,
+            ev_charge_kwh: 0.0,
+            reserve_kwh: 0.0,
+        });
+    }
+
+    let scenario_costs_sek: Vec<f64> = scenario_objectives
+        .into_iter()
+        .map(|obj| solution.eval(obj))
+        .collect();
+
+    Ok(KeplerResult {
+        slots: result_slots,
+        total_cost_sek: solution.eval(objective),
+        is_optimal: true,
+        status_msg: "Optimal (stochastic)".to_string(),
+        solve_time_ms: solve_duration_ms,
+        scenario_costs_sek: Some(scenario_costs_sek),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn base_config() -> KeplerConfig {
+        KeplerConfig {
+            capacity_kwh: 10.0,
+            min_soc_percent: 10.0,
+            max_soc_percent: 100.0,
+            max_charge_power_kw: 5.0,
+            max_discharge_power_kw: 5.0,
+            charge_efficiency: 0.95,
+            discharge_efficiency: 0.95,
+            wear_cost_sek_per_kwh: 0.0,
+            max_export_power_kw: None,
+            max_import_power_kw: None,
+            target_soc_kwh: None,
+            target_soc_penalty_sek: 0.0,
+            terminal_value_sek_kwh: 0.0,
+            ramping_cost_sek_per_kw: 0.0,
+            export_threshold_sek_per_kwh: 0.0,
+            grid_import_limit_kw: None,
+            water_heating_power_kw: 0.0,
+            water_heating_min_kwh: 0.0,
+            water_heating_max_gap_hours: 0.0,
+            water_heated_today_kwh: 0.0,
+            water_comfort_penalty_sek: 0.0,
+            water_min_spacing_hours: 0.0,
+            water_spacing_penalty_sek: 0.0,
+            force_water_on_slots: None,
+            water_block_start_penalty_sek: 0.0,
+            defer_up_to_hours: 0.0,
+            enable_export: true,
+            prevent_simultaneous_charge_discharge: false,
+            max_duration_hours: None,
+            ashp_max_heat_kw: 0.0,
+            space_heat_comfort_penalty_sek: 0.0,
+            boiler_max_heat_kw: 0.0,
+            boiler_efficiency: 0.0,
+            fuel_price_sek_per_kwh: 0.0,
+            reserve_requirement_kw: 0.0,
+        }
+    }
+
+    fn base_slot(hour: i64, import_price: f64, export_price: f64, load_kwh: f64, pv_kwh: f64) -> KeplerInputSlot {
+        let start = Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, 0).unwrap() + chrono::Duration::hours(hour);
+        KeplerInputSlot {
+            start_time: start,
+            end_time: start + chrono::Duration::hours(1),
+            load_kwh,
+            pv_kwh,
+            import_price_sek_kwh: import_price,
+            export_price_sek_kwh: export_price,
+            cop: 1.0,
+            space_heat_kwh: 0.0,
+        }
+    }
+
+    #[test]
+    fn prevent_simultaneous_charge_discharge_forbids_same_slot_cycling() {
+        let mut config = base_config();
+        config.prevent_simultaneous_charge_discharge = true;
+
+        let input = KeplerInput {
+            slots: vec![
+                base_slot(0, 0.5, 0.4, 0.0, 5.0), // cheap import, PV surplus: tempts charging
+                base_slot(1, 2.0, 0.4, 5.0, 0.0), // expensive import: tempts discharging
+            ],
+            initial_soc_kwh: 5.0,
+            ev_sessions: Vec::new(),
+        };
+
+        let result = solve_kepler(&SidecarInput { input, config, scenarios: None }).unwrap();
+        for slot in &result.slots {
+            assert!(
+                slot.charge_kwh < 1e-6 || slot.discharge_kwh < 1e-6,
+                "slot charged {} and discharged {} in the same slot",
+                slot.charge_kwh,
+                slot.discharge_kwh
+            );
+        }
+    }
+
+    #[test]
+    fn ashp_meets_space_heat_demand_when_capacity_allows() {
+        let mut config = base_config();
+        config.ashp_max_heat_kw = 5.0;
+        config.space_heat_comfort_penalty_sek = 1000.0;
+
+        let mut slot = base_slot(0, 1.0, 0.5, 1.0, 0.0);
+        slot.cop = 3.0;
+        slot.space_heat_kwh = 2.0;
+
+        let input = KeplerInput {
+            slots: vec![slot],
+            initial_soc_kwh: 5.0,
+            ev_sessions: Vec::new(),
+        };
+
+        let result = solve_kepler(&SidecarInput { input, config, scenarios: None }).unwrap();
+        assert!((result.slots[0].ashp_heat_kwh - 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn boiler_covers_space_heat_when_ashp_disabled() {
+        let mut config = base_config();
+        config.boiler_max_heat_kw = 5.0;
+        config.boiler_efficiency = 0.9;
+        config.fuel_price_sek_per_kwh = 0.5;
+        config.space_heat_comfort_penalty_sek = 1000.0;
+
+        let mut slot = base_slot(0, 1.0, 0.5, 1.0, 0.0);
+        slot.space_heat_kwh = 2.0;
+
+        let input = KeplerInput {
+            slots: vec![slot],
+            initial_soc_kwh: 5.0,
+            ev_sessions: Vec::new(),
+        };
+
+        let result = solve_kepler(&SidecarInput { input, config, scenarios: None }).unwrap();
+        assert!((result.slots[0].boiler_heat_kwh - 2.0).abs() < 1e-6);
+        assert!(result.slots[0].ashp_heat_kwh < 1e-6);
+    }
+
+    #[test]
+    fn ev_session_reaches_required_energy_by_departure() {
+        let config = base_config();
+
+        let input = KeplerInput {
+            slots: vec![
+                base_slot(0, 0.2, 0.1, 0.5, 0.0),
+                base_slot(1, 2.0, 0.1, 0.5, 0.0),
+                base_slot(2, 0.3, 0.1, 0.5, 0.0),
+            ],
+            initial_soc_kwh: 5.0,
+            ev_sessions: vec![EvSession {
+                arrival_slot: 0,
+                departure_slot: 3,
+                energy_required_kwh: 4.0,
+                max_charge_power_kw: 3.0,
+            }],
+        };
+
+        let result = solve_kepler(&SidecarInput { input, config, scenarios: None }).unwrap();
+        let delivered: f64 = result.slots.iter().map(|s| s.ev_charge_kwh).sum();
+        assert!(delivered >= 4.0 - 1e-6, "delivered only {} kwh of 4.0 required", delivered);
+    }
+
+    #[test]
+    fn reserve_requirement_keeps_discharge_headroom() {
+        let mut config = base_config();
+        config.capacity_kwh = 10.0;
+        config.min_soc_percent = 0.0;
+        config.reserve_requirement_kw = 2.0;
+
+        // Exporting at 0.5 SEK/kWh with no terminal value pulls the optimizer
+        // to discharge as much as it can; the reserve shortfall penalty far
+        // exceeds that revenue, so discharge stops at 3 kWh and the 2 kWh
+        // headroom holds without needing the penalized slack.
+        let input = KeplerInput {
+            slots: vec![base_slot(0, 1.0, 0.5, 1.0, 0.0)],
+            initial_soc_kwh: 5.0,
+            ev_sessions: Vec::new(),
+        };
+
+        let result = solve_kepler(&SidecarInput { input, config, scenarios: None }).unwrap();
+        // `reserve_kwh` appears in no objective term, so any value at or
+        // above the requirement is an equally valid optimum; only the lower
+        // bound is guaranteed.
+        assert!(result.slots[0].reserve_kwh >= 2.0 - 1e-6);
+    }
+
+    fn scenario(probability: f64, prices: &[f64], initial_soc_kwh: f64) -> KeplerScenario {
+        KeplerScenario {
+            probability,
+            input: KeplerInput {
+                slots: prices.iter().enumerate().map(|(i, &p)| base_slot(i as i64, p, 0.1, 1.0, 0.0)).collect(),
+                initial_soc_kwh,
+                ev_sessions: Vec::new(),
+            },
+        }
+    }
+
+    #[test]
+    fn stochastic_rejects_mismatched_scenario_horizons() {
+        let config = base_config();
+        let scenarios = vec![
+            scenario(0.5, &[1.0, 2.0], 5.0),
+            scenario(0.5, &[1.0], 5.0),
+        ];
+        let input = scenarios[0].input.clone();
+
+        let err = solve_kepler(&SidecarInput { input, config, scenarios: Some(scenarios) }).unwrap_err();
+        assert!(err.to_string().contains("slots"));
+    }
+
+    #[test]
+    fn stochastic_reports_one_cost_per_scenario_and_full_horizon_slots() {
+        let mut config = base_config();
+        config.enable_export = false;
+        let scenarios = vec![
+            scenario(0.5, &[1.0, 5.0], 5.0),
+            scenario(0.5, &[1.0, 0.1], 5.0),
+        ];
+        let input = scenarios[0].input.clone();
+
+        let result = solve_kepler(&SidecarInput { input, config, scenarios: Some(scenarios) }).unwrap();
+        assert_eq!(result.scenario_costs_sek.as_ref().unwrap().len(), 2);
+        assert_eq!(result.slots.len(), 2);
+    }
+}